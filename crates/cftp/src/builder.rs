@@ -1,6 +1,6 @@
-use crate::{Ftp, FtpHandler};
+use crate::{CommandFraming, CrlfFraming, Ftp, FtpHandler};
 use futures::{AsyncRead, AsyncWrite};
-use std::marker::PhantomData;
+use std::{collections::HashSet, marker::PhantomData};
 
 #[cfg(feature = "tls")]
 use futures_rustls::rustls::ServerConfig;
@@ -9,6 +9,7 @@ use futures_rustls::rustls::ServerConfig;
 use std::sync::Arc;
 
 #[cfg(feature = "tls")]
+#[derive(Clone)]
 pub struct EncryptionInfo {
     pub implicit: bool,
     pub allow_plaintext: bool,
@@ -22,21 +23,44 @@ impl EncryptionInfo {
     }
 }
 
+#[derive(Clone)]
 pub enum Security {
     NoEncryption,
     #[cfg(feature = "tls")]
     Encryption(EncryptionInfo),
 }
 
-pub struct FtpBuilder<Handler, Stream>
+pub struct FtpBuilder<Handler, Stream, Framing = CrlfFraming>
 where
     Handler: FtpHandler,
     Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Framing: CommandFraming,
 {
     pub(crate) security: Security,
+    pub(crate) framing: Framing,
+    pub(crate) max_download_size: Option<u64>,
+    pub(crate) disabled_features: HashSet<String>,
     __phantom: PhantomData<(Handler, Stream)>,
 }
 
+// derive(Clone) would needlessly require Handler: Clone and Stream: Clone
+impl<Handler, Stream, Framing> Clone for FtpBuilder<Handler, Stream, Framing>
+where
+    Handler: FtpHandler,
+    Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Framing: CommandFraming + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            security: self.security.clone(),
+            framing: self.framing.clone(),
+            max_download_size: self.max_download_size,
+            disabled_features: self.disabled_features.clone(),
+            __phantom: PhantomData,
+        }
+    }
+}
+
 impl<Handler, Stream> Default for FtpBuilder<Handler, Stream>
 where
     Handler: FtpHandler<Io = Stream>,
@@ -46,23 +70,60 @@ where
         Self {
             __phantom: PhantomData,
             security: Security::NoEncryption,
+            framing: CrlfFraming,
+            max_download_size: None,
+            disabled_features: HashSet::new(),
         }
     }
 }
 
-impl<Handler, Stream> FtpBuilder<Handler, Stream>
+impl<Handler, Stream, Framing> FtpBuilder<Handler, Stream, Framing>
 where
     Handler: FtpHandler<Io = Stream>,
     Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Framing: CommandFraming,
 {
     pub async fn build(
         self,
         handler: Handler,
         stream: Stream,
-    ) -> std::io::Result<crate::Ftp<Handler, Stream>> {
+    ) -> std::io::Result<crate::Ftp<Handler, Stream, Framing>> {
         Ftp::new_from_builder(handler, stream, self).await
     }
 
+    /// replaces the default `\r\n` command framing. see [`CommandFraming`].
+    pub fn framing<NewFraming>(self, framing: NewFraming) -> FtpBuilder<Handler, Stream, NewFraming>
+    where
+        NewFraming: CommandFraming,
+    {
+        FtpBuilder {
+            security: self.security,
+            framing,
+            max_download_size: self.max_download_size,
+            disabled_features: self.disabled_features,
+            __phantom: PhantomData,
+        }
+    }
+
+    /// stops advertising `feature` in the FEAT reply, whether it comes from
+    /// [`DEFAULT_FEATURES`](crate::DEFAULT_FEATURES) or [`FtpHandler::features`]. matching is
+    /// case-insensitive, and `"MLST"` also drops a feature line with parameters like
+    /// `"MLST type*;size*;"`.
+    pub fn disable_feature(mut self, feature: impl Into<String>) -> Self {
+        self.disabled_features.insert(feature.into());
+        self
+    }
+
+    /// refuses RETR for files larger than `bytes`, as reported by [`FtpHandler::size`].
+    /// downloads are unlimited by default.
+    ///
+    /// the limit only applies to handlers which implement `size`. the default implementation
+    /// returns `None`, and files of unknown size are always sent.
+    pub fn max_download_size(mut self, bytes: u64) -> Self {
+        self.max_download_size = Some(bytes);
+        self
+    }
+
     #[cfg(feature = "tls")]
     pub fn encryption(mut self, info: EncryptionInfo) -> Self {
         self.security = Security::Encryption(info);
@@ -29,6 +29,7 @@ pub enum SimpleReturnCode {
     ClosingDataConnectionSuccessful = 226,
     UserLoggedIn = 230,
     AuthenticationSuccessful = 234,
+    FileActionOk = 250,
     NeedPassword = 331,
     NeedAccount = 332,
     FileActionPending = 350,
@@ -44,6 +45,7 @@ pub enum SimpleReturnCode {
     ParameterNotImplemented = 504,
     NotLoggedIn = 530,
     NeetAccountForStoringFiles = 532,
+    ProtectionLevelNotSupported = 536,
     FileUnavailable = 550,
     ExceededStorageAllocation = 552,
     FilenameNotAllowed = 553,
@@ -53,6 +55,7 @@ pub enum SimpleReturnCode {
 #[derive(Debug, Clone, PartialEq, Eq, strum_macros::EnumDiscriminants)]
 pub enum FtpResponse {
     Simple(SimpleReturnCode, Option<String>) = 0,
+    Multiline(SimpleReturnCode, Vec<String>) = 1,
     Features(HashSet<String>) = 211,
     NameSystemType(String) = 215,
     ReadyForNewUser(String) = 220,
@@ -70,9 +73,13 @@ impl FtpResponse {
         FtpResponse::Simple(code, Some(msg.into()))
     }
 
+    pub fn multiline(code: SimpleReturnCode, lines: Vec<String>) -> Self {
+        FtpResponse::Multiline(code, lines)
+    }
+
     pub fn code(&self) -> u16 {
         match self {
-            FtpResponse::Simple(code, _) => *code as u16,
+            FtpResponse::Simple(code, _) | FtpResponse::Multiline(code, _) => *code as u16,
             _ => FtpResponseDiscriminants::from(self) as u16,
         }
     }
@@ -83,7 +90,14 @@ impl FtpResponse {
         let mut buf = Vec::new();
         let code = self.code();
 
-        let _ = write!(&mut buf, "{} ", code);
+        // multiline replies open with `xyz-` rather than `xyz `, see RFC 959 section 4.2
+        let separator = match &self {
+            FtpResponse::Features(_) => '-',
+            FtpResponse::Multiline(_, lines) if lines.len() > 1 => '-',
+            _ => ' ',
+        };
+
+        let _ = write!(&mut buf, "{code}{separator}");
 
         match self {
             FtpResponse::ReadyForNewUser(msg)
@@ -115,6 +129,18 @@ impl FtpResponse {
                 let _ = write!(&mut buf, "{code} End");
             }
 
+            FtpResponse::Multiline(_, lines) => {
+                // repeat the code on every line so message text can never be mistaken for the last line
+                let last = lines.len().saturating_sub(1);
+                for (i, line) in lines.iter().enumerate() {
+                    if i > 0 {
+                        let separator = if i == last { ' ' } else { '-' };
+                        let _ = write!(&mut buf, "\r\n{code}{separator}");
+                    }
+                    let _ = write!(&mut buf, "{line}");
+                }
+            }
+
             FtpResponse::FileActionOk(None) | FtpResponse::Simple(_, None) => {}
         }
 
@@ -147,3 +173,28 @@ where
         FtpResponse::simple_msg(self.into(), msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_use_multiline_form() {
+        let bytes = FtpResponse::Features(["UTF8".to_string()].into()).to_bytes();
+        assert_eq!(bytes, b"211-Features:\r\n UTF8\r\n211 End\r\n");
+    }
+
+    #[test]
+    fn multiline_with_one_line_is_single_line() {
+        let bytes =
+            FtpResponse::multiline(SimpleReturnCode::FileActionOk, vec!["done".into()]).to_bytes();
+        assert_eq!(bytes, b"250 done\r\n");
+    }
+
+    #[test]
+    fn multiline_repeats_code_on_every_line() {
+        let lines = vec!["a".into(), "b".into(), "c".into()];
+        let bytes = FtpResponse::multiline(SimpleReturnCode::FileActionOk, lines).to_bytes();
+        assert_eq!(bytes, b"250-a\r\n250-b\r\n250 c\r\n");
+    }
+}
@@ -17,6 +17,16 @@ macro_rules! impl_command {
 			)*
 		}
 
+		impl Command {
+			/// every verb cftp can parse, in the order they were declared.
+			pub const VERBS: &'static [&'static str] = &[$($deserialize),*];
+
+			/// whether `verb` (case-insensitive) is one of [`Command::VERBS`].
+			pub fn is_supported(verb: &str) -> bool {
+				Self::VERBS.iter().any(|v| v.eq_ignore_ascii_case(verb))
+			}
+		}
+
 		impl ::std::str::FromStr for Command {
 			type Err = String;
 
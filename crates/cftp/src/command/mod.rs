@@ -3,9 +3,12 @@ pub mod cwd;
 pub mod impl_command;
 pub mod opts;
 pub mod pass;
+pub mod prot;
+pub mod rest;
 pub mod retr;
 pub mod rnfr;
 pub mod rnto;
+pub mod site;
 pub mod stor;
 pub mod r#type;
 pub mod user;
@@ -38,6 +41,9 @@ impl_command! {
     Opts | "OPTS" => opts,
     Utf8 | "UTF8" => utf8,
     Pbsz | "PBSZ" => pbsz,
+    Prot | "PROT" => prot,
     Rnfr | "RNFR" => rnfr,
     Rnto | "RNTO" => rnto,
+    Rest | "REST" => rest,
+    Site | "SITE" => site,
 }
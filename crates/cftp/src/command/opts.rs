@@ -5,6 +5,15 @@ pub struct Opts {
     pub options: String,
 }
 
+impl Opts {
+    /// splits the options into the command they apply to and its parameters, e.g.
+    /// `MLST type;size;` becomes `("MLST", "type;size;")`.
+    pub fn command(&self) -> (&str, &str) {
+        let (command, params) = self.options.split_once(' ').unwrap_or((&self.options, ""));
+        (command, params.trim())
+    }
+}
+
 impl FromStr for Opts {
     type Err = Infallible;
 
@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Prot {
+    pub level: ProtectionLevel,
+}
+
+/// data channel protection levels from RFC 2228. cftp only implements `Clear` and `Private`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProtectionLevel {
+    Clear,
+    Safe,
+    Confidential,
+    Private,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum ProtectionLevelParseError {
+    #[error("invalid protection level")]
+    InvalidProtectionLevel,
+}
+
+impl FromStr for ProtectionLevel {
+    type Err = ProtectionLevelParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "C" => Ok(ProtectionLevel::Clear),
+            "S" => Ok(ProtectionLevel::Safe),
+            "E" => Ok(ProtectionLevel::Confidential),
+            "P" => Ok(ProtectionLevel::Private),
+            _ => Err(ProtectionLevelParseError::InvalidProtectionLevel),
+        }
+    }
+}
+
+impl FromStr for Prot {
+    type Err = ProtectionLevelParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = s.trim().parse()?;
+        Ok(Self { level })
+    }
+}
@@ -0,0 +1,16 @@
+use std::{num::ParseIntError, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rest {
+    pub offset: u64,
+}
+
+impl FromStr for Rest {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            offset: s.trim().parse()?,
+        })
+    }
+}
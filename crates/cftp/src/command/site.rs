@@ -0,0 +1,59 @@
+use std::{path::PathBuf, str::FromStr};
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Site {
+    pub command: SiteCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SiteCommand {
+    Chmod { mode: u16, path: PathBuf },
+    Other(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum SiteParseError {
+    #[error("invalid permission mode")]
+    InvalidMode,
+    #[error("missing path")]
+    MissingPath,
+}
+
+impl FromStr for Site {
+    type Err = SiteParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, params) = s.split_once(' ').unwrap_or((s, ""));
+
+        if command.eq_ignore_ascii_case("CHMOD") {
+            let (mode, path) = params
+                .trim()
+                .split_once(' ')
+                .ok_or(SiteParseError::MissingPath)?;
+
+            let mode = u16::from_str_radix(mode, 8).map_err(|_| SiteParseError::InvalidMode)?;
+            if mode > 0o7777 {
+                return Err(SiteParseError::InvalidMode);
+            }
+
+            let path = path.trim();
+            if path.is_empty() {
+                return Err(SiteParseError::MissingPath);
+            }
+
+            return Ok(Self {
+                command: SiteCommand::Chmod {
+                    mode,
+                    path: PathBuf::from(path.replace("\\", "/")),
+                },
+            });
+        }
+
+        Ok(Self {
+            command: SiteCommand::Other(s.to_string()),
+        })
+    }
+}
@@ -14,20 +14,60 @@ impl FromStr for Type {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TransferType {
     Ascii,
     Binary,
+    Ebcdic,
+    /// `TYPE L <byte size>`, with the byte size as the client sent it. it's always made of
+    /// digits, but isn't stored as an integer so no size can overflow. `L 8` is parsed as
+    /// [`TransferType::Binary`], since that's what it amounts to on any machine with 8-bit bytes.
+    Local(String),
 }
 
 impl FromStr for TransferType {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.trim().to_uppercase().as_str() {
-            "A" | "ASCII" => Ok(TransferType::Ascii),
-            "I" | "BINARY" => Ok(TransferType::Binary),
+        let s = s.trim().to_uppercase();
+        let (kind, param) = s
+            .split_once(' ')
+            .map(|(kind, param)| (kind, param.trim()))
+            .unwrap_or((&s, ""));
+
+        match (kind, param) {
+            ("A" | "ASCII", "") => Ok(TransferType::Ascii),
+            ("I" | "BINARY", "") => Ok(TransferType::Binary),
+            ("E", _) => Ok(TransferType::Ebcdic),
+            ("L", size) if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) => {
+                Err("invalid byte size")
+            }
+            ("L", size) => match size.parse::<u8>() {
+                Ok(8) => Ok(TransferType::Binary),
+                _ => Ok(TransferType::Local(size.to_string())),
+            },
             _ => Err("invalid transfer type"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_byte_sizes_parse() {
+        assert_eq!("L 8".parse(), Ok(TransferType::Binary));
+        assert_eq!("L 08".parse(), Ok(TransferType::Binary));
+        assert_eq!("L 7".parse(), Ok(TransferType::Local("7".to_string())));
+        assert_eq!("L 300".parse(), Ok(TransferType::Local("300".to_string())));
+
+        let huge = "99999999999999999999999999";
+        let parsed = format!("L {huge}").parse();
+        assert_eq!(parsed, Ok(TransferType::Local(huge.to_string())));
+
+        assert!("L".parse::<TransferType>().is_err());
+        assert!("L x".parse::<TransferType>().is_err());
+        assert!("L +8".parse::<TransferType>().is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use futures::{AsyncRead, AsyncReadExt};
+
+/// splits the control connection into individual commands.
+///
+/// FTP clients are supposed to terminate every command with `\r\n`, and that's what
+/// [`CrlfFraming`] (the default) expects. some embedded or industrial clients don't play by
+/// those rules though (bare `\n`, length prefixes, fixed-size records...), so if you're stuck
+/// talking to one of them you can implement this trait and pass it to
+/// [`FtpBuilder::framing`](crate::FtpBuilder::framing).
+pub trait CommandFraming: Send {
+    /// reads the next command from `reader`, without its terminator.
+    ///
+    /// returning an empty buffer signals that the client has disconnected.
+    fn read_command<R>(&mut self, reader: &mut R) -> impl Future<Output = std::io::Result<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin + Send;
+}
+
+/// the standard `\r\n`-terminated framing described in RFC 959.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CrlfFraming;
+
+impl CommandFraming for CrlfFraming {
+    async fn read_command<R>(&mut self, reader: &mut R) -> std::io::Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut buf = Vec::new();
+
+        loop {
+            let mut byte = [0u8; 1];
+
+            let n = reader.read(&mut byte).await?;
+            if n == 0 {
+                break; // EOF
+            }
+
+            buf.push(byte[0]);
+            let len = buf.len();
+            if len >= 2 && buf[len - 2..] == *b"\r\n" {
+                buf.truncate(len - 2);
+                break;
+            }
+        }
+
+        Ok(buf)
+    }
+}
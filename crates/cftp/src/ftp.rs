@@ -1,7 +1,14 @@
 use crate::{
-    FtpBuilder, FtpHandler, IoFactory, Security,
+    CommandFraming, CrlfFraming, DEFAULT_FEATURES, FtpBuilder, FtpHandler, IoFactory, MLST_FACTS,
+    Security,
     code::{FtpResponse, IntoFtpResponse, SimpleReturnCode},
-    command::Command,
+    command::{
+        Command,
+        opts::Opts,
+        prot::{Prot, ProtectionLevel},
+        site::{Site, SiteCommand},
+        r#type::{TransferType, Type},
+    },
 };
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use std::{collections::HashSet, error::Error, path::PathBuf};
@@ -41,12 +48,14 @@ pub enum TlsUpgradeError {
     Io(#[from] std::io::Error),
 }
 
-pub struct Ftp<Handler, Stream>
+pub struct Ftp<Handler, Stream, Framing = CrlfFraming>
 where
     Handler: FtpHandler,
     Stream: AsyncRead + AsyncWrite + Unpin,
+    Framing: CommandFraming,
 {
     handler: Handler,
+    framing: Framing,
     #[cfg(feature = "tls")]
     reader: MaybeTls<Stream>,
     #[cfg(feature = "tls")]
@@ -56,6 +65,11 @@ where
     io_factory: Option<Handler::Factory>,
     #[cfg(feature = "tls")]
     allow_plaintext: bool,
+    #[cfg(feature = "tls")]
+    data_protection: ProtectionLevel,
+    max_download_size: Option<u64>,
+    disabled_features: HashSet<String>,
+    mlst_facts: Vec<String>,
 }
 
 #[derive(Debug, Error)]
@@ -64,6 +78,32 @@ pub enum ReadError {
     Io(#[from] std::io::Error),
     #[error("failed to parse command: {0}")]
     Parse(String),
+    #[error("invalid command parameters: {0}")]
+    InvalidParams(String),
+}
+
+#[derive(Debug, Error)]
+enum DataConnError {
+    #[error("no passive connection available")]
+    Unavailable,
+    #[cfg(feature = "tls")]
+    #[error("TLS handshake on data connection failed: {0}")]
+    Tls(std::io::Error),
+}
+
+impl DataConnError {
+    fn reply(&self) -> FtpResponse {
+        match self {
+            DataConnError::Unavailable => {
+                FtpResponse::simple(SimpleReturnCode::CommandNotImplemented)
+            }
+            #[cfg(feature = "tls")]
+            DataConnError::Tls(_) => FtpResponse::simple_msg(
+                SimpleReturnCode::CantOpenDataConnection,
+                "TLS negotiation on data connection failed.",
+            ),
+        }
+    }
 }
 
 impl<Handler, Stream> Ftp<Handler, Stream>
@@ -75,43 +115,71 @@ where
         FtpBuilder::default()
     }
 
+    pub fn new_insecure(handler: Handler, stream: Stream) -> Self {
+        Self::new_insecure_with_framing(handler, stream, CrlfFraming)
+    }
+}
+
+impl<Handler, Stream, Framing> Ftp<Handler, Stream, Framing>
+where
+    Handler: FtpHandler<Io = Stream>,
+    Stream: AsyncRead + AsyncWrite + Unpin + Send,
+    Framing: CommandFraming,
+{
     pub async fn new_from_builder(
         handler: Handler,
         stream: Stream,
-        builder: FtpBuilder<Handler, Stream>,
+        builder: FtpBuilder<Handler, Stream, Framing>,
     ) -> std::io::Result<Self> {
-        match builder.security {
-            Security::NoEncryption => Ok(Self::new_insecure(handler, stream)),
+        let framing = builder.framing;
+        let mut ftp = match builder.security {
+            Security::NoEncryption => Self::new_insecure_with_framing(handler, stream, framing),
             #[cfg(feature = "tls")]
             Security::Encryption(enc_info) => {
                 let acceptor = TlsAcceptor::from(enc_info.config);
                 match enc_info.implicit {
                     true => {
                         let tls_stream = acceptor.accept(stream).await?;
-                        Ok(Ftp {
+                        Ftp {
                             handler,
+                            framing,
                             reader: MaybeTls::Tls(Box::new(tls_stream)),
                             io_factory: None,
                             acceptor: Some(acceptor),
                             allow_plaintext: enc_info.allow_plaintext,
-                        })
+                            // implicit FTPS clients expect the data connection to be encrypted too
+                            data_protection: ProtectionLevel::Private,
+                            max_download_size: None,
+                            disabled_features: HashSet::new(),
+                            mlst_facts: default_mlst_facts(),
+                        }
                     }
 
-                    false => Ok(Ftp {
+                    false => Ftp {
                         handler,
+                        framing,
                         reader: MaybeTls::Plain(stream),
                         io_factory: None,
                         acceptor: Some(acceptor),
                         allow_plaintext: enc_info.allow_plaintext,
-                    }),
+                        data_protection: ProtectionLevel::Clear,
+                        max_download_size: None,
+                        disabled_features: HashSet::new(),
+                        mlst_facts: default_mlst_facts(),
+                    },
                 }
             }
-        }
+        };
+
+        ftp.max_download_size = builder.max_download_size;
+        ftp.disabled_features = builder.disabled_features;
+        Ok(ftp)
     }
 
-    pub fn new_insecure(handler: Handler, stream: Stream) -> Self {
+    pub fn new_insecure_with_framing(handler: Handler, stream: Stream, framing: Framing) -> Self {
         Ftp {
             handler,
+            framing,
             #[cfg(feature = "tls")]
             acceptor: None,
             #[cfg(feature = "tls")]
@@ -121,6 +189,11 @@ where
             io_factory: None,
             #[cfg(feature = "tls")]
             allow_plaintext: true,
+            #[cfg(feature = "tls")]
+            data_protection: ProtectionLevel::Clear,
+            max_download_size: None,
+            disabled_features: HashSet::new(),
+            mlst_facts: default_mlst_facts(),
         }
     }
 
@@ -196,10 +269,9 @@ where
                     break user;
                 }
 
-                Command::Opts(ref opts) => {
+                Command::Opts(opts) => {
                     tracing::info!(%opts.options, "received OPTS command before authentication");
-                    self.write(FtpResponse::simple(SimpleReturnCode::Ok))
-                        .await?;
+                    self.opts(opts).await?;
                     continue;
                 }
 
@@ -235,6 +307,7 @@ where
         }
 
         let mut to_rename = None;
+        let mut restart_offset = None;
 
         self.write(FtpResponse::simple(SimpleReturnCode::UserLoggedIn))
             .await?;
@@ -255,6 +328,15 @@ where
                     .await?;
                     continue;
                 }
+                Err(ReadError::InvalidParams(parse_error)) => {
+                    tracing::error!(err = %parse_error, "invalid command parameters");
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::SyntaxError,
+                        parse_error,
+                    ))
+                    .await?;
+                    continue;
+                }
                 Err(e) => {
                     tracing::error!(err = %e, "failed to read command");
                     break;
@@ -277,14 +359,35 @@ where
                 }
 
                 Command::Cwd(cwd) => {
-                    if !self.handler.set_cwd(&PathBuf::from(cwd.path)).await {
+                    let path = PathBuf::from(cwd.path);
+                    if !self.handler.set_cwd(&path).await {
                         self.write(FtpResponse::simple(SimpleReturnCode::FileUnavailable))
                             .await?;
                         continue;
                     }
 
-                    self.write(FtpResponse::simple(SimpleReturnCode::Ok))
-                        .await?;
+                    let mut lines = match self.handler.cwd_message(&path).await {
+                        Some(message) => message.lines().map(String::from).collect(),
+                        None => Vec::new(),
+                    };
+
+                    lines.push("Directory successfully changed.".to_string());
+                    self.write(FtpResponse::multiline(
+                        SimpleReturnCode::FileActionOk,
+                        lines,
+                    ))
+                    .await?;
+                }
+
+                Command::Type(Type {
+                    change_to: change_to @ (TransferType::Ebcdic | TransferType::Local(_)),
+                }) => {
+                    tracing::info!(?change_to, "unsupported transfer type requested");
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::ParameterNotImplemented,
+                        "Command not implemented for that parameter.",
+                    ))
+                    .await?;
                 }
 
                 Command::Type(t) => {
@@ -320,11 +423,13 @@ where
                 }
 
                 Command::List(_) => {
-                    let Some(mut data_stream) = self.passive_conn().await else {
-                        tracing::error!("no passive connection available for LIST command");
-                        self.write(FtpResponse::simple(SimpleReturnCode::CommandNotImplemented))
-                            .await?;
-                        continue;
+                    let mut data_stream = match self.passive_conn().await {
+                        Ok(data_stream) => data_stream,
+                        Err(e) => {
+                            tracing::error!(err = %e, "failed to open data connection for LIST command");
+                            self.write(e.reply()).await?;
+                            continue;
+                        }
                     };
 
                     let ls = match self.handler.ls().await {
@@ -353,6 +458,11 @@ where
                         }
                     }
 
+                    // over TLS, this is what sends close_notify
+                    if let Err(e) = data_stream.close().await {
+                        tracing::warn!(err = %e, "failed to close data connection");
+                    }
+
                     self.write(FtpResponse::simple(
                         SimpleReturnCode::ClosingDataConnectionSuccessful,
                     ))
@@ -365,14 +475,72 @@ where
                 }
 
                 Command::Retr(retr) => {
-                    let Some(mut data_stream) = self.passive_conn().await else {
-                        tracing::error!("no passive connection available for RETR command");
-                        self.write(FtpResponse::simple(SimpleReturnCode::CommandNotImplemented))
-                            .await?;
+                    // taken up front so a RETR which fails early can't leave the offset behind
+                    let offset = restart_offset.take();
+
+                    if let Some(max) = self.max_download_size
+                        && let Some(size) = self.handler.size(&retr.file).await
+                        && size > max
+                    {
+                        tracing::warn!(
+                            name = %retr.file.display(),
+                            size,
+                            max,
+                            "refusing to send file above the download size limit",
+                        );
+                        self.write(FtpResponse::simple_msg(
+                            SimpleReturnCode::FileUnavailable,
+                            "File exceeds the maximum download size.",
+                        ))
+                        .await?;
                         continue;
+                    }
+
+                    let reader = match self.handler.open_read(&retr.file).await {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            tracing::error!(err = %e, name = %retr.file.display(), "failed to open file");
+                            self.write(FtpResponse::simple(SimpleReturnCode::FileUnavailable))
+                                .await?;
+                            continue;
+                        }
                     };
 
-                    match self.handler.read(&retr.file, &mut data_stream).await {
+                    let mut data_stream = match self.passive_conn().await {
+                        Ok(data_stream) => data_stream,
+                        Err(e) => {
+                            tracing::error!(err = %e, "failed to open data connection for RETR command");
+                            self.write(e.reply()).await?;
+                            continue;
+                        }
+                    };
+
+                    let result = match (reader, offset) {
+                        (Some(reader), offset) => {
+                            copy_from(reader, offset.unwrap_or(0), &mut data_stream)
+                                .await
+                                .map(|bytes| {
+                                    tracing::debug!(bytes, "copied file to data connection")
+                                })
+                                .map_err(HandleError::Io)
+                        }
+                        (None, Some(offset)) => self
+                            .handler
+                            .read_from(&retr.file, offset, &mut data_stream)
+                            .await
+                            .map_err(HandleError::Handler),
+                        (None, None) => self
+                            .handler
+                            .read(&retr.file, &mut data_stream)
+                            .await
+                            .map_err(HandleError::Handler),
+                    };
+
+                    if let Err(e) = data_stream.close().await {
+                        tracing::warn!(err = %e, "failed to close data connection");
+                    }
+
+                    match result {
                         Ok(()) => {
                             tracing::info!(
                                 name = %retr.file.display(),
@@ -392,14 +560,59 @@ where
                 }
 
                 Command::Stor(stor) => {
-                    let Some(mut data_stream) = self.passive_conn().await else {
-                        tracing::error!("no passive connection available for STOR command");
-                        self.write(FtpResponse::simple(SimpleReturnCode::CommandNotImplemented))
-                            .await?;
+                    // restarted uploads aren't supported, and writing the tail as the whole file
+                    // would corrupt it, so refuse rather than ignore the offset
+                    if let Some(offset) = restart_offset.take() {
+                        tracing::warn!(offset, name = %stor.file.display(), "refusing restarted upload");
+                        self.write(FtpResponse::simple_msg(
+                            SimpleReturnCode::FileUnavailable,
+                            "Restarted uploads not supported.",
+                        ))
+                        .await?;
                         continue;
+                    }
+
+                    // the data connection comes first, since opening the file for writing will
+                    // usually truncate it, and a failed upload shouldn't destroy what was there
+                    let mut data_stream = match self.passive_conn().await {
+                        Ok(data_stream) => data_stream,
+                        Err(e) => {
+                            tracing::error!(err = %e, "failed to open data connection for STOR command");
+                            self.write(e.reply()).await?;
+                            continue;
+                        }
                     };
 
-                    match self.handler.write(&stor.file, &mut data_stream).await {
+                    let writer = match self.handler.open_write(&stor.file).await {
+                        Ok(writer) => writer,
+                        Err(e) => {
+                            tracing::error!(err = %e, name = %stor.file.display(), "failed to open file");
+                            if let Err(e) = data_stream.close().await {
+                                tracing::warn!(err = %e, "failed to close data connection");
+                            }
+
+                            self.write(FtpResponse::simple(SimpleReturnCode::FileUnavailable))
+                                .await?;
+                            continue;
+                        }
+                    };
+
+                    let result = match writer {
+                        Some(writer) => copy_into(&mut data_stream, writer)
+                            .await
+                            .map(|bytes| tracing::debug!(bytes, "copied data connection to file"))
+                            .map_err(HandleError::Io),
+                        None => self
+                            .handler
+                            .write(&stor.file, &mut data_stream)
+                            .await
+                            .map_err(HandleError::Handler),
+                    };
+                    if let Err(e) = data_stream.close().await {
+                        tracing::warn!(err = %e, "failed to close data connection");
+                    }
+
+                    match result {
                         Ok(()) => {
                             tracing::info!(
                                 name = %stor.file.display(),
@@ -420,21 +633,44 @@ where
 
                 Command::Feat(_) => {
                     let mut features = self.handler.features().await;
-                    // we also support UTF8, SIZE, MDTM, MFMT, MLST and MLSD
-                    let default_features = vec!["UTF8", "SIZE", "MDTM", "MFMT", "MLST", "MLSD"]
-                        .into_iter()
-                        .map(String::from)
-                        .collect::<HashSet<String>>();
+                    // the defaults go through the same verb list the parser uses, so a command
+                    // cftp can't handle is never advertised
+                    features.extend(
+                        DEFAULT_FEATURES
+                            .iter()
+                            .filter(|feature| {
+                                Command::is_supported(feature.split(' ').next().unwrap_or(feature))
+                            })
+                            .copied()
+                            .map(String::from),
+                    );
+
+                    // RFC 3659 wants the MLST line to list every fact, with the active ones starred
+                    if features.remove("MLST") {
+                        let facts: String = MLST_FACTS
+                            .iter()
+                            .map(|fact| match self.mlst_facts.iter().any(|f| f == fact) {
+                                true => format!("{fact}*;"),
+                                false => format!("{fact};"),
+                            })
+                            .collect();
+                        features.insert(format!("MLST {facts}"));
+                    }
 
-                    features.extend(default_features);
+                    features.retain(|feature| {
+                        let name = feature.split(' ').next().unwrap_or(feature);
+                        !self.disabled_features.iter().any(|disabled| {
+                            disabled.eq_ignore_ascii_case(feature)
+                                || disabled.eq_ignore_ascii_case(name)
+                        })
+                    });
 
                     self.write(FtpResponse::Features(features)).await?;
                 }
 
                 Command::Opts(opts) => {
                     tracing::info!(%opts.options, "received OPTS command");
-                    self.write(FtpResponse::simple(SimpleReturnCode::Ok))
-                        .await?;
+                    self.opts(opts).await?;
                 }
 
                 Command::Utf8(_) => {
@@ -486,6 +722,98 @@ where
                     }
                 }
 
+                Command::Rest(rest) => {
+                    tracing::info!(offset = rest.offset, "received REST command");
+                    // REST 0 is how clients explicitly clear a previous offset
+                    restart_offset = (rest.offset > 0).then_some(rest.offset);
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::FileActionPending,
+                        format!("Restarting at {}.", rest.offset),
+                    ))
+                    .await?;
+                }
+
+                Command::Site(Site {
+                    command: SiteCommand::Chmod { mode, path },
+                }) => match self.handler.chmod(&path, mode).await {
+                    Ok(true) => {
+                        tracing::info!(
+                            path = %path.display(),
+                            "changed permissions to {mode:o}",
+                        );
+                        self.write(FtpResponse::simple_msg(
+                            SimpleReturnCode::Ok,
+                            "SITE CHMOD command successful.",
+                        ))
+                        .await?;
+                    }
+                    Ok(false) => {
+                        self.write(FtpResponse::simple_msg(
+                            SimpleReturnCode::CommandNotImplemented,
+                            "SITE CHMOD not supported",
+                        ))
+                        .await?;
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            err = %e,
+                            path = %path.display(),
+                            "failed to change permissions",
+                        );
+                        self.write(e.into_ftp_response()).await?;
+                    }
+                },
+
+                Command::Site(Site {
+                    command: SiteCommand::Other(command),
+                }) => {
+                    tracing::info!(%command, "unsupported SITE command received");
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::CommandNotImplemented,
+                        "unsupported SITE command",
+                    ))
+                    .await?;
+                }
+
+                #[cfg(feature = "tls")]
+                Command::Prot(Prot {
+                    level: ProtectionLevel::Private,
+                }) if !matches!(self.reader, MaybeTls::Tls(_)) => {
+                    tracing::warn!("received PROT P before TLS upgrade");
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::BadSequence,
+                        "Please use AUTH TLS before sending PROT P.",
+                    ))
+                    .await?;
+                }
+
+                #[cfg(feature = "tls")]
+                Command::Prot(Prot {
+                    level: level @ (ProtectionLevel::Clear | ProtectionLevel::Private),
+                }) => {
+                    tracing::info!(?level, "changing data protection level");
+                    self.data_protection = level;
+                    self.write(FtpResponse::simple(SimpleReturnCode::Ok))
+                        .await?;
+                }
+
+                #[cfg(not(feature = "tls"))]
+                Command::Prot(Prot {
+                    level: ProtectionLevel::Clear,
+                }) => {
+                    self.write(FtpResponse::simple(SimpleReturnCode::Ok))
+                        .await?;
+                }
+
+                Command::Prot(prot) => {
+                    tracing::info!(?prot.level, "unsupported protection level requested");
+                    self.write(FtpResponse::simple_msg(
+                        SimpleReturnCode::ProtectionLevelNotSupported,
+                        "Requested PROT level not supported.",
+                    ))
+                    .await?;
+                }
+
                 Command::Pbsz(_) => {
                     self.write(FtpResponse::simple(SimpleReturnCode::Ok))
                         .await?;
@@ -496,11 +824,57 @@ where
         Ok(())
     }
 
-    async fn passive_conn(&mut self) -> Option<Handler::Io> {
+    async fn opts(&mut self, opts: Opts) -> Result<(), std::io::Error> {
+        let (command, params) = opts.command();
+        if !command.eq_ignore_ascii_case("MLST") {
+            return self.write(FtpResponse::simple(SimpleReturnCode::Ok)).await;
+        }
+
+        // unsupported facts are silently dropped, and the reply tells the client what's left
+        let requested: Vec<&str> = params.split(';').map(str::trim).collect();
+        self.mlst_facts = MLST_FACTS
+            .iter()
+            .filter(|supported| requested.iter().any(|r| r.eq_ignore_ascii_case(supported)))
+            .map(|fact| fact.to_string())
+            .collect();
+
+        let facts: String = self.mlst_facts.iter().map(|f| format!("{f};")).collect();
+        let msg = format!("MLST OPTS {facts}");
+        self.write(FtpResponse::simple_msg(
+            SimpleReturnCode::Ok,
+            msg.trim_end(),
+        ))
+        .await
+    }
+
+    async fn passive_conn(&mut self) -> Result<DataStream<Handler::Io>, DataConnError> {
+        // without a PASV there's nothing to open, so don't promise a data connection
+        if self.io_factory.is_none() {
+            return Err(DataConnError::Unavailable);
+        }
+
         self.write(FtpResponse::simple(SimpleReturnCode::OpeningDataConnection))
             .await
-            .ok()?;
-        self.io_factory.as_mut()?.create_io().await
+            .map_err(|_| DataConnError::Unavailable)?;
+        let io = self
+            .io_factory
+            .as_mut()
+            .ok_or(DataConnError::Unavailable)?
+            .create_io()
+            .await
+            .ok_or(DataConnError::Unavailable)?;
+
+        #[cfg(feature = "tls")]
+        let io = match self.data_protection {
+            ProtectionLevel::Private => {
+                let acceptor = self.acceptor.as_ref().ok_or(DataConnError::Unavailable)?;
+                let tls_stream = acceptor.accept(io).await.map_err(DataConnError::Tls)?;
+                MaybeTls::Tls(Box::new(tls_stream))
+            }
+            _ => MaybeTls::Plain(io),
+        };
+
+        Ok(io)
     }
 
     #[cfg(feature = "tls")]
@@ -534,23 +908,7 @@ where
     }
 
     async fn read(&mut self) -> Result<CommandRead, ReadError> {
-        let mut buf = Vec::new();
-
-        loop {
-            let mut byte = [0u8; 1];
-
-            let n = self.reader.read(&mut byte).await?;
-            if n == 0 {
-                break; // EOF
-            }
-
-            buf.push(byte[0]);
-            let len = buf.len();
-            if len >= 2 && buf[len - 2..] == *b"\r\n" {
-                buf.truncate(len - 2);
-                break;
-            }
-        }
+        let buf = self.framing.read_command(&mut self.reader).await?;
 
         let command_str = String::from_utf8(buf)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
@@ -561,7 +919,15 @@ where
 
         command_str
             .parse()
-            .map_err(ReadError::Parse)
+            .map_err(|e| {
+                // a verb cftp knows with parameters it can't parse is a syntax error (501), not
+                // an unimplemented command (502)
+                let verb = command_str.split_whitespace().next().unwrap_or_default();
+                match Command::is_supported(verb) {
+                    true => ReadError::InvalidParams(e),
+                    false => ReadError::Parse(e),
+                }
+            })
             .map(CommandRead::Command)
     }
 
@@ -575,7 +941,684 @@ where
     }
 }
 
+/// skips the first `offset` bytes of `reader`, then copies the rest into `writer`. returns the
+/// number of bytes copied.
+async fn copy_from<R, W>(mut reader: R, offset: u64, writer: &mut W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if offset > 0 {
+        futures::io::copy((&mut reader).take(offset), &mut futures::io::sink()).await?;
+    }
+
+    futures::io::copy(reader, writer).await
+}
+
+/// copies all of `reader` into `writer`, then closes `writer` so nothing is left buffered.
+/// returns the number of bytes copied.
+async fn copy_into<R, W>(reader: &mut R, mut writer: W) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bytes = futures::io::copy(reader, &mut writer).await?;
+    writer.close().await?;
+    Ok(bytes)
+}
+
+fn default_mlst_facts() -> Vec<String> {
+    MLST_FACTS.iter().copied().map(String::from).collect()
+}
+
+#[cfg(feature = "tls")]
+type DataStream<Io> = MaybeTls<Io>;
+#[cfg(not(feature = "tls"))]
+type DataStream<Io> = Io;
+
 enum CommandRead {
     Command(Command),
     Disconnect,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileListing, PassiveConn};
+    use futures::{
+        AsyncReadExt, StreamExt,
+        channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded},
+    };
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr},
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+    use tokio::io::DuplexStream;
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    type Io = Compat<DuplexStream>;
+
+    #[derive(Debug, Error)]
+    #[error("no such file")]
+    struct NotFound;
+
+    impl From<NotFound> for SimpleReturnCode {
+        fn from(_: NotFound) -> Self {
+            SimpleReturnCode::FileUnavailable
+        }
+    }
+
+    /// hands out one end of an in-memory pipe as the passive connection.
+    struct MemoryFactory(Option<Io>);
+
+    impl IoFactory for MemoryFactory {
+        type Io = Io;
+
+        async fn create_io(&mut self) -> Option<Self::Io> {
+            self.0.take()
+        }
+    }
+
+    /// serves files from memory, sending the client's end of every data connection to the test.
+    struct MemoryHandler {
+        files: Files,
+        data_conns: UnboundedSender<Io>,
+        cwd_message: Option<String>,
+        modes: HashMap<PathBuf, u16>,
+        supports_chmod: bool,
+        /// hand out readers and writers through `open_read`/`open_write`, rather than going
+        /// through `read`/`write`.
+        open_files: bool,
+    }
+
+    impl MemoryHandler {
+        /// a handler serving `files`, along with the receiving end of its data connections.
+        fn new(files: &[(&str, &[u8])]) -> (Self, UnboundedReceiver<Io>) {
+            let (tx, rx) = unbounded();
+            let handler = MemoryHandler {
+                files: Arc::new(Mutex::new(
+                    files
+                        .iter()
+                        .map(|(name, data)| (PathBuf::from(name), data.to_vec()))
+                        .collect(),
+                )),
+                data_conns: tx,
+                cwd_message: None,
+                modes: HashMap::new(),
+                supports_chmod: true,
+                open_files: false,
+            };
+
+            (handler, rx)
+        }
+    }
+
+    impl FtpHandler for MemoryHandler {
+        type Io = Io;
+        type Factory = MemoryFactory;
+        type Err = NotFound;
+
+        async fn authenticate(&mut self, _: &str, _: &str) -> Result<bool, Self::Err> {
+            Ok(true)
+        }
+
+        async fn cwd(&mut self) -> Option<&Path> {
+            Some(Path::new("/"))
+        }
+
+        async fn set_cwd(&mut self, _: &Path) -> bool {
+            true
+        }
+
+        async fn cwd_message(&mut self, _: &Path) -> Option<String> {
+            self.cwd_message.clone()
+        }
+
+        async fn ls(&mut self) -> Result<Vec<FileListing>, Self::Err> {
+            let files = self.files.lock().unwrap();
+            let listing = files.iter().map(|(path, file)| FileListing {
+                name: path.display().to_string(),
+                is_dir: false,
+                permissions: self.modes.get(path).copied().unwrap_or(0o644),
+                size: file.len() as u64,
+                modified: chrono::Utc::now(),
+                owner: "owner".to_string(),
+                group: "group".to_string(),
+            });
+
+            Ok(listing.collect())
+        }
+
+        async fn chmod(&mut self, path: &Path, mode: u16) -> Result<bool, Self::Err> {
+            if !self.supports_chmod {
+                return Ok(false);
+            }
+
+            if !self.files.lock().unwrap().contains_key(path) {
+                return Err(NotFound);
+            }
+
+            self.modes.insert(path.to_path_buf(), mode);
+            Ok(true)
+        }
+
+        async fn rename(&mut self, _: &Path, _: &Path) -> Result<(), Self::Err> {
+            Ok(())
+        }
+
+        async fn passive_conn(&mut self) -> Result<PassiveConn<Io, MemoryFactory>, Self::Err> {
+            let (server, client) = tokio::io::duplex(1024);
+            self.data_conns.unbounded_send(client.compat()).unwrap();
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 2121));
+            Ok(PassiveConn::new(addr, MemoryFactory(Some(server.compat()))))
+        }
+
+        // not a default any more, but advertised here so the FEAT MLST line can be checked
+        async fn features(&mut self) -> HashSet<String> {
+            HashSet::from(["MLST".to_string()])
+        }
+
+        async fn size(&mut self, path: &Path) -> Option<u64> {
+            self.files.lock().unwrap().get(path).map(|f| f.len() as u64)
+        }
+
+        async fn open_read(
+            &mut self,
+            path: &Path,
+        ) -> Result<Option<impl AsyncRead + Unpin + Send + use<>>, Self::Err> {
+            if !self.open_files {
+                return Ok(None);
+            }
+
+            let file = self.files.lock().unwrap().get(path).cloned();
+            Ok(Some(futures::io::Cursor::new(file.ok_or(NotFound)?)))
+        }
+
+        async fn read<W>(&mut self, path: &Path, writer: &mut W) -> Result<(), Self::Err>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            // only reachable when open_read falls back to it
+            assert!(!self.open_files, "read called despite open_read");
+            let file = self.files.lock().unwrap().get(path).cloned();
+            writer
+                .write_all(&file.ok_or(NotFound)?)
+                .await
+                .map_err(|_| NotFound)
+        }
+
+        async fn open_write(
+            &mut self,
+            path: &Path,
+        ) -> Result<Option<impl AsyncWrite + Unpin + Send + use<>>, Self::Err> {
+            if !self.open_files {
+                return Ok(None);
+            }
+
+            // truncate straight away, the way creating a real file would
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), Vec::new());
+
+            Ok(Some(MemoryWriter {
+                path: path.to_path_buf(),
+                buf: Vec::new(),
+                files: self.files.clone(),
+            }))
+        }
+
+        async fn write<R>(&mut self, path: &Path, reader: &mut R) -> Result<(), Self::Err>
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            let mut file = Vec::new();
+            reader.read_to_end(&mut file).await.map_err(|_| NotFound)?;
+            self.files.lock().unwrap().insert(path.to_path_buf(), file);
+            Ok(())
+        }
+    }
+
+    type Files = Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>;
+
+    /// buffers an upload, and only stores it once closed.
+    struct MemoryWriter {
+        path: PathBuf,
+        buf: Vec<u8>,
+        files: Files,
+    }
+
+    impl AsyncWrite for MemoryWriter {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.get_mut().buf.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            let file = std::mem::take(&mut this.buf);
+            this.files.lock().unwrap().insert(this.path.clone(), file);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Client<Control = Io> {
+        control: Control,
+        data_conns: UnboundedReceiver<Io>,
+    }
+
+    impl Client {
+        /// starts a session with `files` and logs in.
+        async fn connect(files: &[(&str, &[u8])], max_download_size: u64) -> Self {
+            let (handler, data_conns) = MemoryHandler::new(files);
+            let builder = Ftp::builder().max_download_size(max_download_size);
+            Self::login(handler, data_conns, builder).await
+        }
+
+        /// starts a session with an already configured handler and logs in.
+        async fn login(
+            handler: MemoryHandler,
+            data_conns: UnboundedReceiver<Io>,
+            builder: FtpBuilder<MemoryHandler, Io>,
+        ) -> Self {
+            let mut client = Self::start(handler, data_conns, builder).await;
+            assert!(client.command("USER a").await.starts_with("331"));
+            assert!(client.command("PASS b").await.starts_with("230"));
+            client
+        }
+
+        /// starts a session, stopping after the welcome message.
+        async fn start(
+            handler: MemoryHandler,
+            data_conns: UnboundedReceiver<Io>,
+            builder: FtpBuilder<MemoryHandler, Io>,
+        ) -> Self {
+            let (server, client) = tokio::io::duplex(1024);
+            let ftp = builder.build(handler, server.compat()).await.unwrap();
+            tokio::spawn(ftp.handle());
+
+            let mut client = Client {
+                control: client.compat(),
+                data_conns,
+            };
+
+            assert!(client.reply().await.starts_with("220"));
+            client
+        }
+    }
+
+    impl<Control> Client<Control>
+    where
+        Control: AsyncRead + AsyncWrite + Unpin,
+    {
+        async fn reply(&mut self) -> String {
+            let mut line = Vec::new();
+            while !line.ends_with(b"\r\n") {
+                let mut byte = [0u8; 1];
+                self.control.read_exact(&mut byte).await.unwrap();
+                line.push(byte[0]);
+            }
+
+            String::from_utf8(line).unwrap()
+        }
+
+        async fn command(&mut self, command: &str) -> String {
+            self.control
+                .write_all(format!("{command}\r\n").as_bytes())
+                .await
+                .unwrap();
+            self.reply().await
+        }
+
+        /// reads every line of a multiline reply, up to and including the last one.
+        async fn multiline_reply(&mut self) -> String {
+            let mut reply = self.reply().await;
+            while reply
+                .rsplit("\r\n")
+                .nth(1)
+                .is_some_and(|line| line.as_bytes()[3] == b'-')
+            {
+                reply.push_str(&self.reply().await);
+            }
+
+            reply
+        }
+
+        /// runs PASV and STOR, uploading `data`.
+        async fn stor(&mut self, path: &str, data: &[u8]) {
+            assert!(self.command("PASV").await.starts_with("227"));
+            let mut conn = self.data_conns.next().await.unwrap();
+            assert!(
+                self.command(&format!("STOR {path}"))
+                    .await
+                    .starts_with("150")
+            );
+
+            conn.write_all(data).await.unwrap();
+            conn.close().await.unwrap();
+            assert!(self.reply().await.starts_with("226"));
+        }
+
+        /// runs PASV and LIST, returning the listing.
+        async fn list(&mut self) -> String {
+            assert!(self.command("PASV").await.starts_with("227"));
+            let mut data = self.data_conns.next().await.unwrap();
+            assert!(self.command("LIST").await.starts_with("150"));
+
+            let mut listing = String::new();
+            data.read_to_string(&mut listing).await.unwrap();
+            assert!(self.reply().await.starts_with("226"));
+            listing
+        }
+
+        /// runs PASV and RETR, returning the downloaded bytes.
+        async fn retr(&mut self, path: &str) -> Vec<u8> {
+            assert!(self.command("PASV").await.starts_with("227"));
+            let mut data = self.data_conns.next().await.unwrap();
+            assert!(
+                self.command(&format!("RETR {path}"))
+                    .await
+                    .starts_with("150")
+            );
+
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).await.unwrap();
+            assert!(self.reply().await.starts_with("226"));
+            buf
+        }
+    }
+
+    /// keeps a copy of everything read through it, i.e. the raw bytes that crossed the wire.
+    #[cfg(feature = "tls")]
+    struct Recorder<S> {
+        inner: S,
+        read: Vec<u8>,
+    }
+
+    #[cfg(feature = "tls")]
+    impl<S> AsyncRead for Recorder<S>
+    where
+        S: AsyncRead + Unpin,
+    {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+            let poll = std::pin::Pin::new(&mut this.inner).poll_read(cx, buf);
+            if let std::task::Poll::Ready(Ok(n)) = poll {
+                this.read.extend_from_slice(&buf[..n]);
+            }
+
+            poll
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    impl<S> AsyncWrite for Recorder<S>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.get_mut().inner).poll_close(cx)
+        }
+    }
+
+    const SMALL: &[u8] = b"0123456789";
+
+    #[tokio::test]
+    async fn opts_mlst_echoes_each_accepted_fact_once() {
+        let mut client = Client::connect(&[], u64::MAX).await;
+
+        let reply = client.command("OPTS MLST type;size;foo;TYPE;").await;
+        assert_eq!(reply, "200 MLST OPTS type;size;\r\n");
+
+        assert!(client.command("FEAT").await.starts_with("211-"));
+        let mut mlst = None;
+        loop {
+            let line = client.reply().await;
+            if line.starts_with("211 ") {
+                break;
+            }
+            if line.starts_with(" MLST ") {
+                mlst = Some(line);
+            }
+        }
+
+        let facts = "type*;size*;modify;unix.mode;unix.owner;unix.group;";
+        assert_eq!(mlst.unwrap(), format!(" MLST {facts}\r\n"));
+    }
+
+    #[tokio::test]
+    async fn rest_zero_clears_offset() {
+        let mut client = Client::connect(&[("small", SMALL)], u64::MAX).await;
+
+        assert!(client.command("REST 5").await.starts_with("350"));
+        assert!(client.command("REST 0").await.starts_with("350"));
+        assert_eq!(client.retr("small").await, SMALL);
+    }
+
+    #[tokio::test]
+    async fn rest_applies_to_next_retr_only() {
+        let mut client = Client::connect(&[("small", SMALL)], u64::MAX).await;
+
+        assert!(client.command("REST 4").await.starts_with("350"));
+        assert_eq!(client.retr("small").await, b"456789");
+        assert_eq!(client.retr("small").await, SMALL);
+    }
+
+    #[tokio::test]
+    async fn retr_copies_opened_reader() {
+        let (mut handler, data_conns) = MemoryHandler::new(&[("small", SMALL)]);
+        handler.open_files = true;
+        let mut client = Client::login(handler, data_conns, Ftp::builder()).await;
+
+        assert_eq!(client.retr("small").await, SMALL);
+        assert!(client.command("REST 4").await.starts_with("350"));
+        assert_eq!(client.retr("small").await, b"456789");
+        assert_eq!(client.retr("small").await, SMALL);
+    }
+
+    #[tokio::test]
+    async fn stor_copies_into_opened_writer() {
+        let (mut handler, data_conns) = MemoryHandler::new(&[("small", SMALL)]);
+        handler.open_files = true;
+        let mut client = Client::login(handler, data_conns, Ftp::builder()).await;
+
+        // with no PASV there's no data connection, so the file mustn't be opened (and truncated)
+        assert!(client.command("STOR small").await.starts_with("502"));
+        assert_eq!(client.retr("small").await, SMALL);
+
+        // the writer only stores the upload when it's closed
+        client.stor("small", b"abc").await;
+        assert_eq!(client.retr("small").await, b"abc");
+    }
+
+    #[tokio::test]
+    async fn refused_retr_clears_offset() {
+        let big = [0u8; 100];
+        let mut client = Client::connect(&[("small", SMALL), ("big", &big)], 50).await;
+
+        assert!(client.command("REST 100").await.starts_with("350"));
+        assert!(client.command("RETR big").await.starts_with("550"));
+        assert_eq!(client.retr("small").await, SMALL);
+    }
+
+    #[tokio::test]
+    async fn rest_refuses_stor() {
+        let mut client = Client::connect(&[("small", SMALL)], u64::MAX).await;
+
+        assert!(client.command("REST 5").await.starts_with("350"));
+        assert!(client.command("STOR small").await.starts_with("550"));
+        assert_eq!(client.retr("small").await, SMALL);
+
+        client.stor("small", b"abc").await;
+        assert_eq!(client.retr("small").await, b"abc");
+    }
+
+    #[tokio::test]
+    async fn cwd_sends_handler_message() {
+        let (mut handler, data_conns) = MemoryHandler::new(&[]);
+        handler.cwd_message = Some("welcome to /pub\nplease be nice".to_string());
+        let mut client = Client::login(handler, data_conns, Ftp::builder()).await;
+
+        client.control.write_all(b"CWD /pub\r\n").await.unwrap();
+        assert_eq!(
+            client.multiline_reply().await,
+            "250-welcome to /pub\r\n250-please be nice\r\n250 Directory successfully changed.\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn cwd_without_message_replies_250() {
+        let mut client = Client::connect(&[], u64::MAX).await;
+
+        let reply = client.command("CWD /pub").await;
+        assert_eq!(reply, "250 Directory successfully changed.\r\n");
+    }
+
+    #[tokio::test]
+    async fn site_chmod_shows_in_list() {
+        let mut client = Client::connect(&[("small", SMALL)], u64::MAX).await;
+
+        assert!(client.list().await.starts_with("-rw-r--r-- "));
+        let reply = client.command("SITE CHMOD 600 small").await;
+        assert_eq!(reply, "200 SITE CHMOD command successful.\r\n");
+        assert!(client.list().await.starts_with("-rw------- "));
+    }
+
+    #[tokio::test]
+    async fn site_chmod_unsupported_replies_502() {
+        let (mut handler, data_conns) = MemoryHandler::new(&[("small", SMALL)]);
+        handler.supports_chmod = false;
+        let mut client = Client::login(handler, data_conns, Ftp::builder()).await;
+
+        let reply = client.command("SITE CHMOD 600 small").await;
+        assert_eq!(reply, "502 SITE CHMOD not supported\r\n");
+        assert!(client.list().await.starts_with("-rw-r--r-- "));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn explicit_ftps_encrypts_retr() {
+        use crate::EncryptionInfo;
+        use futures_rustls::{
+            TlsConnector,
+            pki_types::{
+                PrivateKeyDer, ServerName,
+                pem::{PemObject, SectionKind},
+            },
+            rustls::{ClientConfig, RootCertStore, ServerConfig},
+        };
+        use rcgen::CertifiedKey;
+        use std::sync::Arc;
+
+        const SECRET: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+        // a self-signed cert, as in the runner, which the client is told to trust
+        let CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key =
+            PrivateKeyDer::from_pem(SectionKind::PrivateKey, signing_key.serialize_der()).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let name = ServerName::try_from("localhost").unwrap();
+
+        let (handler, data_conns) = MemoryHandler::new(&[("secret", SECRET)]);
+        let encryption = EncryptionInfo::builder(Arc::new(server_config)).build();
+        let builder = Ftp::builder().encryption(encryption);
+        let mut client = Client::start(handler, data_conns, builder).await;
+
+        assert!(client.command("AUTH TLS").await.starts_with("234"));
+        let control = connector
+            .connect(name.clone(), client.control)
+            .await
+            .unwrap();
+        let mut client = Client {
+            control,
+            data_conns: client.data_conns,
+        };
+
+        assert!(client.command("USER a").await.starts_with("331"));
+        assert!(client.command("PASS b").await.starts_with("230"));
+        assert!(client.command("PBSZ 0").await.starts_with("200"));
+        assert!(client.command("PROT P").await.starts_with("200"));
+        assert!(client.command("PASV").await.starts_with("227"));
+
+        let data = client.data_conns.next().await.unwrap();
+        assert!(client.command("RETR secret").await.starts_with("150"));
+        let recorder = Recorder {
+            inner: data,
+            read: Vec::new(),
+        };
+        let mut data = connector.connect(name, recorder).await.unwrap();
+
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf).await.unwrap();
+        assert!(client.reply().await.starts_with("226"));
+        assert_eq!(buf, SECRET);
+
+        let raw = &data.get_ref().0.read;
+        assert!(raw.len() > SECRET.len());
+        assert!(!raw.windows(SECRET.len()).any(|window| window == SECRET));
+    }
+
+    #[tokio::test]
+    async fn type_replies() {
+        let mut client = Client::connect(&[], u64::MAX).await;
+
+        assert!(client.command("TYPE I").await.starts_with("200"));
+        assert!(client.command("TYPE L 8").await.starts_with("200"));
+        assert!(client.command("TYPE E").await.starts_with("504"));
+        assert!(client.command("TYPE L 7").await.starts_with("504"));
+        assert!(client.command("TYPE L 300").await.starts_with("504"));
+        assert!(client.command("TYPE L").await.starts_with("501"));
+        assert!(client.command("TYPE L x").await.starts_with("501"));
+        assert!(client.command("TYPE X").await.starts_with("501"));
+        assert!(client.command("XYZZY").await.starts_with("502"));
+    }
+}
@@ -1,10 +1,36 @@
-use std::{collections::HashSet, error::Error, fmt, net::SocketAddr, path::Path};
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 use chrono::{DateTime, Datelike, Timelike, Utc};
 use futures::{AsyncRead, AsyncWrite};
 
 use crate::code::{FtpResponse, IntoFtpResponse, Port};
 
+/// the features cftp advertises in its FEAT reply by default, on top of whatever
+/// [`FtpHandler::features`] returns. only commands cftp actually handles belong here, and FEAT
+/// skips any entry [`Command::is_supported`](crate::command::Command::is_supported) rejects. use
+/// [`FtpBuilder::disable_feature`](crate::FtpBuilder::disable_feature) to drop any of them.
+pub const DEFAULT_FEATURES: &[&str] = &["UTF8", "REST STREAM"];
+
+/// the MLST facts cftp accepts in `OPTS MLST`, and lists on the `MLST` line of its FEAT reply if
+/// [`FtpHandler::features`] advertises MLST. there's no MLST or MLSD command yet, so the client's
+/// selection is only recorded for now.
+pub const MLST_FACTS: &[&str] = &[
+    "type",
+    "size",
+    "modify",
+    "unix.mode",
+    "unix.owner",
+    "unix.group",
+];
+
 pub trait FtpHandler: Send + Sync {
     type Io: AsyncRead + AsyncWrite + Unpin;
     type Factory: IoFactory<Io = Self::Io>;
@@ -22,9 +48,26 @@ pub trait FtpHandler: Send + Sync {
 
     fn cwd(&mut self) -> impl Future<Output = Option<&Path>>;
     fn set_cwd(&mut self, path: &Path) -> impl Future<Output = bool>;
+
+    /// a message to show the client after it successfully changes into `path`, like the
+    /// `.message` files served by wu-ftpd and proftpd. it's sent as part of the 250 reply
+    /// to CWD, and may span multiple lines.
+    fn cwd_message(&mut self, _path: &Path) -> impl Future<Output = Option<String>> {
+        async { None }
+    }
     fn ls(&mut self) -> impl Future<Output = Result<Vec<FileListing>, Self::Err>>;
     fn rename(&mut self, from: &Path, to: &Path) -> impl Future<Output = Result<(), Self::Err>>;
 
+    /// changes the permission bits of `path` in response to `SITE CHMOD`.
+    ///
+    /// returning `Ok(true)` means the change was applied, and from then on [`FtpHandler::ls`]
+    /// is expected to report `mode` in [`FileListing::permissions`] for that file, since that's
+    /// how clients check the change took effect. returning `Ok(false)` (the default) tells the
+    /// client SITE CHMOD isn't supported.
+    fn chmod(&mut self, _path: &Path, _mode: u16) -> impl Future<Output = Result<bool, Self::Err>> {
+        async { Ok(false) }
+    }
+
     fn passive_conn(
         &mut self,
     ) -> impl Future<Output = Result<crate::PassiveConn<Self::Io, Self::Factory>, Self::Err>>;
@@ -33,10 +76,38 @@ pub trait FtpHandler: Send + Sync {
         async { "UNIX Type: L8".to_string() }
     }
 
+    /// extra features to advertise in the FEAT reply. these are merged with
+    /// [`DEFAULT_FEATURES`], so there's no need to repeat them here.
     fn features(&mut self) -> impl Future<Output = HashSet<String>> {
         async { HashSet::new() }
     }
 
+    /// the size of `path` in bytes, or `None` if it's unknown. this is used to enforce
+    /// [`FtpBuilder::max_download_size`](crate::FtpBuilder::max_download_size) before a
+    /// RETR opens the data connection.
+    fn size(&mut self, _path: &Path) -> impl Future<Output = Option<u64>> {
+        async { None }
+    }
+
+    /// an alternative to [`FtpHandler::read`] for backends which can simply hand back a reader,
+    /// like an opened file. if this returns `Ok(Some(reader))`, cftp copies it to the data
+    /// connection itself (skipping any REST offset) and `read` is never called, so it can be
+    /// left as a stub.
+    ///
+    /// the reader can't borrow from the handler, so implementations spell the return type as
+    /// `Result<Option<impl AsyncRead + Unpin + Send + use<>>, Self::Err>`. the default returns
+    /// `Ok(None)`, which falls back to `read`.
+    fn open_read(
+        &mut self,
+        _path: &Path,
+    ) -> impl Future<Output = Result<Option<impl AsyncRead + Unpin + Send + use<Self>>, Self::Err>>
+    {
+        async { Ok(None::<futures::io::Empty>) }
+    }
+
+    /// streams `path` into `writer`. this suits backends which produce data bit by bit rather
+    /// than handing out a reader (e.g. a networked store with its own chunking); if you can just
+    /// open something, [`FtpHandler::open_read`] is less work.
     fn read<W>(
         &mut self,
         path: &Path,
@@ -45,6 +116,47 @@ pub trait FtpHandler: Send + Sync {
     where
         W: AsyncWrite + Unpin + Send;
 
+    /// like [`FtpHandler::read`], but starts `offset` bytes into the file. this is what a RETR
+    /// following a REST command ends up calling.
+    ///
+    /// the default implementation calls `read` and throws away the first `offset` bytes, so you
+    /// should override it if your storage backend can seek.
+    fn read_from<W>(
+        &mut self,
+        path: &Path,
+        offset: u64,
+        writer: &mut W,
+    ) -> impl Future<Output = Result<(), Self::Err>>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        async move {
+            let mut writer = SkipWriter {
+                inner: writer,
+                remaining: offset,
+            };
+
+            self.read(path, &mut writer).await
+        }
+    }
+
+    /// the mirror image of [`FtpHandler::open_read`]: if this returns `Ok(Some(writer))`, cftp
+    /// copies the data connection into it and closes it once the upload is done, and `write` is
+    /// never called.
+    ///
+    /// as with `open_read`, the writer can't borrow from the handler. the default returns
+    /// `Ok(None)`, which falls back to `write`.
+    fn open_write(
+        &mut self,
+        _path: &Path,
+    ) -> impl Future<Output = Result<Option<impl AsyncWrite + Unpin + Send + use<Self>>, Self::Err>>
+    {
+        async { Ok(None::<futures::io::Sink>) }
+    }
+
+    /// streams the upload from `reader` into `path`. like [`FtpHandler::read`], this is for
+    /// backends which need to consume the data themselves; if you can just open something to
+    /// write into, [`FtpHandler::open_write`] is less work.
     fn write<R>(
         &mut self,
         path: &Path,
@@ -54,6 +166,62 @@ pub trait FtpHandler: Send + Sync {
         R: AsyncRead + Unpin + Send;
 }
 
+/// discards the first `remaining` bytes written to it, then forwards everything to `inner`.
+struct SkipWriter<'a, W> {
+    inner: &'a mut W,
+    remaining: u64,
+}
+
+impl<W> AsyncWrite for SkipWriter<'_, W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.remaining > 0 {
+            let skipped = this.remaining.min(buf.len() as u64);
+            this.remaining -= skipped;
+            return Poll::Ready(Ok(skipped as usize));
+        }
+
+        Pin::new(&mut *this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// builds a fresh [`FtpHandler`] for every connection accepted by `Ftp::serve`, so each
+/// session gets its own independent state.
+///
+/// this is implemented for any `FnMut(SocketAddr) -> impl FtpHandler`, so a closure is
+/// usually all you need.
+pub trait HandlerFactory {
+    type Handler: FtpHandler;
+    fn create_handler(&mut self, peer: SocketAddr) -> Self::Handler;
+}
+
+impl<F, Handler> HandlerFactory for F
+where
+    F: FnMut(SocketAddr) -> Handler,
+    Handler: FtpHandler,
+{
+    type Handler = Handler;
+
+    fn create_handler(&mut self, peer: SocketAddr) -> Self::Handler {
+        self(peer)
+    }
+}
+
 // this trait could be better-designed (type Err, Option<T> -> Result<T, Err>) but i cba right now
 pub trait IoFactory {
     type Io: AsyncRead + AsyncWrite + Unpin;
@@ -195,3 +363,20 @@ impl fmt::Display for FileListing {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+
+    #[test]
+    fn default_features_are_supported() {
+        for feature in DEFAULT_FEATURES {
+            let verb = feature.split(' ').next().unwrap_or(feature);
+            assert!(
+                Command::is_supported(verb),
+                "{feature} is advertised but not handled"
+            );
+        }
+    }
+}
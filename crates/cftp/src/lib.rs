@@ -7,6 +7,7 @@ pub mod command;
 pub mod tcp;
 
 mod builder;
+mod framing;
 mod ftp;
 mod handler;
 
@@ -14,6 +15,7 @@ mod handler;
 mod tls;
 
 pub use builder::*;
+pub use framing::*;
 pub use ftp::*;
 pub use handler::*;
 
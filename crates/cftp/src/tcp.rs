@@ -1,10 +1,18 @@
-use std::net::IpAddr;
+use std::{
+    future::Pending,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-use crate::{IoFactory, PassiveConn};
+use crate::{CommandFraming, Ftp, FtpBuilder, FtpHandler, HandlerFactory, IoFactory, PassiveConn};
+use futures::{FutureExt, StreamExt, stream::FuturesUnordered};
 use thiserror::Error;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
+/// how long [`Serve::run`] waits before accepting again after an accept error.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Error)]
 pub enum TcpFactoryError {
     #[error("failed to pick unused port for passive connection")]
@@ -69,3 +77,213 @@ impl IoFactory for TcpFactory {
         Some(stream.compat())
     }
 }
+
+impl<Handler> Ftp<Handler, Compat<TcpStream>>
+where
+    Handler: FtpHandler<Io = Compat<TcpStream>>,
+{
+    /// runs the accept loop for you: every connection accepted from `listener` gets a fresh
+    /// handler from `factory` and is set up according to `builder`.
+    ///
+    /// by default, every session is driven on the task which runs the returned [`Serve`], so
+    /// nothing needs to be spawned. use [`Serve::spawn_with`] to hand sessions off to your
+    /// runtime instead, and [`Serve::with_graceful_shutdown`] to stop accepting connections.
+    /// if you need more control than this, you can always write the loop yourself with
+    /// [`FtpBuilder::build`].
+    pub fn serve<Factory, Framing>(
+        listener: TcpListener,
+        factory: Factory,
+        builder: FtpBuilder<Handler, Compat<TcpStream>, Framing>,
+    ) -> Serve<Handler, Factory, Framing>
+    where
+        Factory: HandlerFactory<Handler = Handler>,
+        Framing: CommandFraming + Clone,
+    {
+        Serve {
+            listener,
+            factory,
+            builder,
+            spawn: None,
+            shutdown: std::future::pending(),
+        }
+    }
+}
+
+/// the accept loop returned by [`Ftp::serve`]. nothing happens until you call [`Serve::run`].
+pub struct Serve<
+    Handler,
+    Factory,
+    Framing,
+    Spawn = fn(Session<Handler, Framing>),
+    Shutdown = Pending<()>,
+> where
+    Handler: FtpHandler<Io = Compat<TcpStream>>,
+    Framing: CommandFraming,
+{
+    listener: TcpListener,
+    factory: Factory,
+    builder: FtpBuilder<Handler, Compat<TcpStream>, Framing>,
+    spawn: Option<Spawn>,
+    shutdown: Shutdown,
+}
+
+impl<Handler, Factory, Framing, Spawn, Shutdown> Serve<Handler, Factory, Framing, Spawn, Shutdown>
+where
+    Handler: FtpHandler<Io = Compat<TcpStream>>,
+    Factory: HandlerFactory<Handler = Handler>,
+    Framing: CommandFraming + Clone,
+    Spawn: FnMut(Session<Handler, Framing>),
+    Shutdown: Future<Output = ()>,
+{
+    /// hands every new session to `spawn` rather than driving it in place. this is where you
+    /// plug in your runtime, e.g. `|session| { tokio::spawn(session.run()); }`.
+    ///
+    /// spawned sessions aren't tracked, so graceful shutdown won't wait for them.
+    pub fn spawn_with<NewSpawn>(
+        self,
+        spawn: NewSpawn,
+    ) -> Serve<Handler, Factory, Framing, NewSpawn, Shutdown>
+    where
+        NewSpawn: FnMut(Session<Handler, Framing>),
+    {
+        Serve {
+            listener: self.listener,
+            factory: self.factory,
+            builder: self.builder,
+            spawn: Some(spawn),
+            shutdown: self.shutdown,
+        }
+    }
+
+    /// stops accepting connections once `signal` completes. sessions driven in place are
+    /// allowed to finish before [`Serve::run`] returns.
+    ///
+    /// cftp has no way of telling a session to stop, so in-place sessions only finish when
+    /// their clients disconnect, and a single idle client can keep `run` from returning. if
+    /// that matters, race `run` against a timeout of your own.
+    pub fn with_graceful_shutdown<NewShutdown>(
+        self,
+        signal: NewShutdown,
+    ) -> Serve<Handler, Factory, Framing, Spawn, NewShutdown>
+    where
+        NewShutdown: Future<Output = ()>,
+    {
+        Serve {
+            listener: self.listener,
+            factory: self.factory,
+            builder: self.builder,
+            spawn: self.spawn,
+            shutdown: signal,
+        }
+    }
+
+    pub async fn run(self) {
+        let Serve {
+            listener,
+            mut factory,
+            builder,
+            mut spawn,
+            shutdown,
+        } = self;
+
+        let shutdown = shutdown.fuse();
+        futures::pin_mut!(shutdown);
+
+        let mut sessions = FuturesUnordered::new();
+        let mut backoff = false;
+
+        loop {
+            let delay = backoff;
+            let accept = async {
+                // errors like EMFILE tend to persist for a while, so don't spin on them
+                if delay {
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+
+                listener.accept().await
+            };
+
+            futures::select! {
+                _ = shutdown => {
+                    tracing::info!(
+                        sessions = sessions.len(),
+                        "shutting down, no longer accepting connections",
+                    );
+                    break;
+                }
+
+                accepted = accept.fuse() => {
+                    let (stream, addr) = match accepted {
+                        Ok(a) => a,
+                        Err(e) => {
+                            tracing::error!(
+                                err = %e,
+                                "failed to accept connection, retrying in {ACCEPT_ERROR_BACKOFF:?}",
+                            );
+                            backoff = true;
+                            continue;
+                        }
+                    };
+
+                    backoff = false;
+
+                    tracing::info!(%addr, "accepted connection");
+                    let session = Session {
+                        handler: factory.create_handler(addr),
+                        stream,
+                        builder: builder.clone(),
+                        addr,
+                    };
+
+                    match &mut spawn {
+                        Some(spawn) => spawn(session),
+                        None => sessions.push(session.run()),
+                    }
+                }
+
+                _ = sessions.select_next_some() => {}
+            }
+        }
+
+        while sessions.next().await.is_some() {}
+    }
+}
+
+/// a single accepted connection, waiting to be run. see [`Serve::spawn_with`].
+pub struct Session<Handler, Framing>
+where
+    Handler: FtpHandler<Io = Compat<TcpStream>>,
+    Framing: CommandFraming,
+{
+    handler: Handler,
+    stream: TcpStream,
+    builder: FtpBuilder<Handler, Compat<TcpStream>, Framing>,
+    addr: SocketAddr,
+}
+
+impl<Handler, Framing> Session<Handler, Framing>
+where
+    Handler: FtpHandler<Io = Compat<TcpStream>>,
+    Framing: CommandFraming,
+{
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// sets up the connection (including the TLS handshake for implicit FTPS) and handles
+    /// it until the client disconnects. errors are logged rather than returned.
+    pub async fn run(self) {
+        let addr = self.addr;
+        let ftp = match self.builder.build(self.handler, self.stream.compat()).await {
+            Ok(ftp) => ftp,
+            Err(e) => {
+                tracing::error!(%addr, err = %e, "failed to set up connection");
+                return;
+            }
+        };
+
+        if let Err(e) = ftp.handle().await {
+            tracing::error!(%addr, err = %e, "error handling client");
+        }
+    }
+}
@@ -111,6 +111,9 @@ impl FtpHandler for Handler {
     ///
     /// of course, you don't have to stream if your storage backend has no support, you're always
     /// allowed to just read it into a buffer. but you shouldn't ^_^
+    ///
+    /// if all you'd do here is open a file and copy it into `writer`, implement `open_read`
+    /// instead and return the opened file -- cftp will do the copying for you.
     async fn read<W>(&mut self, path: &Path, writer: &mut W) -> Result<(), Self::Err>
     where
         W: AsyncWrite + Unpin + Send,
@@ -120,6 +123,9 @@ impl FtpHandler for Handler {
 
     /// similarly to `read`, this method allows you to stream data from the client to your storage backend.
     /// see the comments above for more details.
+    ///
+    /// likewise, if all you'd do here is create a file and copy `reader` into it, implement
+    /// `open_write` instead and return the created file.
     async fn write<R>(&mut self, path: &Path, reader: &mut R) -> Result<(), Self::Err>
     where
         R: AsyncRead + Unpin + Send,
@@ -5,7 +5,6 @@ mod handler;
 use crate::handler::Handler;
 use cftp::{
     EncryptionInfo, Ftp,
-    io::TokioAsyncReadCompatExt,
     pki_types::{
         PrivateKeyDer,
         pem::{PemObject, SectionKind},
@@ -37,18 +36,24 @@ async fn main() -> anyhow::Result<()> {
             .with_single_cert(vec![cert.der().clone()], key)?,
     );
 
-    while let Ok((stream, addr)) = listener.accept().await {
-        let ftp = Ftp::builder()
-            .encryption(EncryptionInfo::builder(config.clone()).build())
-            .build(Handler, stream.compat()) // .compat() to convert Tokio TcpStream to futures AsyncRead + AsyncWrite, because cftp is runtime-agnostic
-            .await?;
-
-        tokio::spawn(async move {
-            if let Err(e) = ftp.handle().await {
-                tracing::error!(%addr, %e, "error handling client");
-            }
-        });
-    }
+    // `Ftp::serve` runs the accept loop for us, building a fresh `Handler` for every connection.
+    // cftp is runtime-agnostic, so we tell it how to spawn sessions onto tokio. if you need
+    // more control, you can still accept connections yourself and pass each one to
+    // `Ftp::builder().build(handler, stream.compat())` (.compat() converts a Tokio TcpStream
+    // into a futures AsyncRead + AsyncWrite).
+    Ftp::serve(
+        listener,
+        |_| Handler,
+        Ftp::builder().encryption(EncryptionInfo::builder(config).build()),
+    )
+    .spawn_with(|session| {
+        tokio::spawn(session.run());
+    })
+    .with_graceful_shutdown(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .run()
+    .await;
 
     Ok(())
 }